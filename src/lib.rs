@@ -0,0 +1,1912 @@
+use std::{
+    cell::RefCell,
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{stdin, stdout, BufRead, BufReader, Cursor, Write},
+    mem::{discriminant, Discriminant},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Instant,
+};
+
+pub struct VM {
+    ip: i64,
+    program: Vec<Instruction>,
+    code: Vec<(Op, Direction)>,
+    /// How often each instruction has run, tracked only when the program
+    /// uses `here_count`.
+    counts: Option<Vec<u64>>,
+    labels: HashMap<String, i64>,
+    data: VecDeque<i64>,
+    input: VecDeque<char>,
+    reader: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    profiling: bool,
+    profile: HashMap<Discriminant<Op>, u64>,
+    rng: u64,
+    start: Instant,
+    clock: Option<i64>,
+    logger: Option<Box<dyn FnMut(u8, i64)>>,
+    asker: Option<Box<dyn FnMut() -> Option<i64>>>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> Self {
+        Self {
+            ip: 0,
+            program: Vec::new(),
+            code: Vec::new(),
+            counts: None,
+            labels: HashMap::new(),
+            data: VecDeque::new(),
+            input: VecDeque::new(),
+            reader: Box::new(BufReader::new(stdin())),
+            output: Box::new(stdout()),
+            profiling: false,
+            profile: HashMap::new(),
+            rng: RandomState::new().build_hasher().finish(),
+            start: Instant::now(),
+            clock: None,
+            logger: None,
+            asker: None,
+        }
+    }
+
+    /// Creates a VM whose `rand` opcode produces a reproducible sequence.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            rng: seed,
+            ..Self::new()
+        }
+    }
+
+    /// Enables or disables counting how often each opcode is executed.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiling = enabled;
+    }
+
+    /// Returns the opcode counts gathered while profiling, most frequent
+    /// first. Literal pushes and label references are counted together as
+    /// `push`.
+    pub fn profile(&self) -> Vec<(&'static str, u64)> {
+        let mut profile = self
+            .profile
+            .iter()
+            .map(|(op, count)| (op_name(*op), *count))
+            .collect::<Vec<_>>();
+        profile.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        profile
+    }
+
+    /// Pins the value `time` pushes, or goes back to the real clock with
+    /// `None`.
+    pub fn set_clock(&mut self, millis: Option<i64>) {
+        self.clock = millis;
+    }
+
+    /// Registers the callback the `log` opcode reports records to, as
+    /// `(level, value)` with levels 0 = info, 1 = warn and 2 = error.
+    pub fn set_logger<F: FnMut(u8, i64) + 'static>(&mut self, logger: F) {
+        self.logger = Some(Box::new(logger));
+    }
+
+    /// Registers the callback the `ask` opcode takes values from. When it
+    /// returns `None`, `execute` stops with `VmError::Yield` and can be
+    /// called again to retry once a value is available.
+    pub fn set_ask_callback<F: FnMut() -> Option<i64> + 'static>(&mut self, asker: F) {
+        self.asker = Some(Box::new(asker));
+    }
+
+    /// Replaces the source `read` and `readc` take input from (stdin by
+    /// default), discarding anything still buffered from the old one.
+    pub fn set_input<R: BufRead + 'static>(&mut self, reader: R) {
+        self.input.clear();
+        self.reader = Box::new(reader);
+    }
+
+    /// Replaces the sink printing opcodes write to (stdout by default).
+    pub fn set_output<W: Write + 'static>(&mut self, writer: W) {
+        self.output = Box::new(writer);
+    }
+
+    /// Clears the runtime state (instruction pointer, deque and buffered
+    /// input) while keeping the loaded program.
+    pub fn reset(&mut self) {
+        self.ip = 0;
+        self.data.clear();
+        self.input.clear();
+        if let Some(counts) = &mut self.counts {
+            counts.fill(0);
+        }
+    }
+
+    /// Loads `program` once and runs it against each input in turn, resetting
+    /// the VM in between. Each result holds the final contents of the deque,
+    /// left to right.
+    pub fn run_batch(
+        &mut self,
+        program: &str,
+        inputs: Vec<String>,
+    ) -> Vec<Result<Vec<i64>, VmError>> {
+        if let Err(e) = self.load_program(program.to_owned()) {
+            return inputs
+                .iter()
+                .map(|_| Err(VmError::Error(e.clone())))
+                .collect();
+        }
+        inputs
+            .into_iter()
+            .map(|input| {
+                self.reset();
+                self.set_input(Cursor::new(input));
+                self.execute()?;
+                Ok(self.data.iter().copied().collect())
+            })
+            .collect()
+    }
+
+    fn pop(&mut self, dir: &Direction) -> Result<i64, String> {
+        match dir {
+            Direction::Left => self
+                .data
+                .pop_front()
+                .ok_or("Could not pop from front of deque.".into()),
+            Direction::Right => self
+                .data
+                .pop_back()
+                .ok_or("Could not pop from back of deque.".into()),
+        }
+    }
+
+    /// Estimates the number of bytes currently held by the VM, including the
+    /// deque, buffered input, the loaded program, the label table and any
+    /// execution counts or profile.
+    pub fn memory_bytes(&self) -> usize {
+        let program = self
+            .program
+            .iter()
+            .map(|instruction| instruction.op.capacity())
+            .sum::<usize>()
+            + self.program.capacity() * size_of::<Instruction>()
+            + self.code.capacity() * size_of::<(Op, Direction)>();
+        let labels = self
+            .labels
+            .keys()
+            .map(|label| label.capacity() + size_of::<(String, i64)>())
+            .sum::<usize>();
+        let counts = self
+            .counts
+            .as_ref()
+            .map_or(0, |counts| counts.capacity() * size_of::<u64>());
+        let profile = self.profile.capacity() * size_of::<(Discriminant<Op>, u64)>();
+        size_of::<Self>()
+            + self.data.capacity() * size_of::<i64>()
+            + self.input.capacity() * size_of::<char>()
+            + program
+            + labels
+            + counts
+            + profile
+    }
+
+    /// Releases any spare capacity held by the deque.
+    pub fn shrink_to_fit(&mut self) {
+        self.data.shrink_to_fit();
+    }
+
+    fn push(&mut self, dir: &Direction, val: i64) {
+        match dir {
+            Direction::Left => self.data.push_front(val),
+            Direction::Right => self.data.push_back(val),
+        }
+    }
+
+    /// Loads a program from source text. Includes are resolved relative to
+    /// the current directory.
+    pub fn load_program(&mut self, program: String) -> Result<(), String> {
+        let mut preprocessor = Preprocessor::default();
+        preprocessor.expand(&program, Path::new("."), None)?;
+        self.load_tokens(preprocessor.tokens)
+    }
+
+    /// Loads a program from a file. Includes are resolved relative to the
+    /// file's directory.
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let mut preprocessor = Preprocessor::default();
+        preprocessor.expand_file(path)?;
+        self.load_tokens(preprocessor.tokens)
+    }
+
+    fn load_tokens(&mut self, tokens: Vec<Token>) -> Result<(), String> {
+        let mut items = Vec::new();
+        // open `while` loops as (id, line)
+        let mut loops = Vec::new();
+        let mut next_loop = 0;
+        // directions set by `dirpush`, as (direction, line)
+        let mut contexts = Vec::new();
+        let mut tokens = tokens.into_iter();
+        while let Some(Token {
+            text: inst,
+            location,
+        }) = tokens.next()
+        {
+            match inst.as_str() {
+                "dirpush" => {
+                    let direction = match tokens.next() {
+                        Some(token) if token.text.eq_ignore_ascii_case("left") => Direction::Left,
+                        Some(token) if token.text.eq_ignore_ascii_case("right") => Direction::Right,
+                        _ => return Err(format!("{}: dirpush requires left or right.", location)),
+                    };
+                    contexts.push((direction, location));
+                    continue;
+                }
+                "dirpop" => {
+                    contexts
+                        .pop()
+                        .ok_or(format!("{}: dirpop without a matching dirpush.", location))?;
+                    continue;
+                }
+                _ => {}
+            }
+            // convert text to instructions
+            let (direction, op) = if let Some(op) = inst.strip_prefix('!') {
+                (Direction::Left, op)
+            } else if let Some(op) = inst.strip_suffix('!') {
+                (Direction::Right, op)
+            } else if let Some(label) = inst.strip_suffix(':') {
+                items.push(Item::Label(label.to_ascii_lowercase()));
+                continue;
+            } else if let Some((direction, _)) = contexts.last() {
+                // unmarked tokens take the direction of the enclosing dirpush
+                (*direction, inst.as_str())
+            } else {
+                return Err(format!("{}: Invalid instruction {}.", location, inst));
+            };
+            let instruction = |op: &str| {
+                Item::Instruction(Instruction {
+                    op: op.to_owned(),
+                    direction,
+                    location: location.clone(),
+                })
+            };
+            // loop labels contain a space so they can't clash with a source label
+            match op {
+                "while" => {
+                    // pop the condition and leave the loop once it is zero
+                    items.push(Item::Label(format!("while {}", next_loop)));
+                    items.push(instruction(&format!("endwhile {}", next_loop)));
+                    items.push(instruction("jmpifz"));
+                    loops.push((next_loop, location.clone()));
+                    next_loop += 1;
+                }
+                "endwhile" => {
+                    let (id, _) = loops
+                        .pop()
+                        .ok_or(format!("{}: endwhile without a matching while.", location))?;
+                    items.push(instruction(&format!("while {}", id)));
+                    items.push(instruction("jmp"));
+                    items.push(Item::Label(format!("endwhile {}", id)));
+                }
+                _ => items.push(instruction(op)),
+            }
+        }
+        if let Some((_, location)) = loops.pop() {
+            return Err(format!("{}: while without a matching endwhile.", location));
+        }
+        if let Some((_, location)) = contexts.pop() {
+            return Err(format!("{}: dirpush without a matching dirpop.", location));
+        }
+        // build everything before touching `self`, so a failed load leaves
+        // the previous program intact
+        let (program, labels) = resolve_labels(items);
+        let code = compile(&program, &labels)?;
+        self.counts = code
+            .iter()
+            .any(|(op, _)| matches!(op, Op::HereCount))
+            .then(|| vec![0; code.len()]);
+        self.program = program;
+        self.labels = labels;
+        self.code = code;
+        Ok(())
+    }
+
+    fn add(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, a + b);
+        Ok(())
+    }
+
+    fn sub(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, b - a);
+        Ok(())
+    }
+
+    fn min(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, a.min(b));
+        Ok(())
+    }
+
+    fn max(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, a.max(b));
+        Ok(())
+    }
+
+    /// Replaces the whole deque with the GCD of its elements. An empty deque
+    /// yields 0, since gcd(0, 0) = 0.
+    fn gcdall(&mut self, dir: &Direction) -> Result<(), String> {
+        let result = self
+            .data
+            .iter()
+            .fold(0, |acc, x| gcd(acc, x.unsigned_abs()));
+        let result =
+            i64::try_from(result).map_err(|_| format!("GCD {} does not fit in an i64.", result))?;
+        self.data.clear();
+        self.push(dir, result);
+        Ok(())
+    }
+
+    /// Pops a divisor and then a dividend, and pushes the quotient followed
+    /// by the remainder, so the remainder ends up outermost. Both round
+    /// toward zero like Rust's `/` and `%`.
+    fn divmod(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        if a == 0 {
+            return Err("Division by zero.".into());
+        }
+        let quotient = b
+            .checked_div(a)
+            .ok_or(format!("Cannot divide {} by {}.", b, a))?;
+        self.push(dir, quotient);
+        self.push(dir, b - quotient * a);
+        Ok(())
+    }
+
+    fn jmp(&mut self, dir: &Direction) -> Result<(), String> {
+        self.ip = self.pop(dir)?;
+        Ok(())
+    }
+
+    /// Jumps back to the start of the program. A non-zero operand clears the
+    /// deque first; zero keeps it.
+    fn restart(&mut self, dir: &Direction) -> Result<(), String> {
+        if self.pop(dir)? != 0 {
+            self.data.clear();
+        }
+        self.ip = 0;
+        Ok(())
+    }
+
+    fn jmpif(&mut self, dir: &Direction) -> Result<bool, String> {
+        let addr = self.pop(dir)?;
+        let cond = self.pop(dir)?;
+        if cond != 0 {
+            self.ip = addr;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn jmpifz(&mut self, dir: &Direction) -> Result<bool, String> {
+        let addr = self.pop(dir)?;
+        let cond = self.pop(dir)?;
+        if cond == 0 {
+            self.ip = addr;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Pops a condition and fails unless it is truthy. Like `jmpif`, any
+    /// non-zero value counts as true.
+    fn assert(&mut self, dir: &Direction) -> Result<(), String> {
+        let cond = self.pop(dir)?;
+        if cond == 0 {
+            return Err(format!("Assertion failed at ip {}.", self.ip));
+        }
+        Ok(())
+    }
+
+    fn swap(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, a);
+        self.push(dir, b);
+        Ok(())
+    }
+
+    fn move_(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        self.push(&dir.invert(), a);
+        Ok(())
+    }
+
+    /// Moves n elements one at a time to the opposite end, reversing their
+    /// order like pouring one stack onto another.
+    fn shift(&mut self, dir: &Direction) -> Result<(), String> {
+        let n = self.pop(dir)?;
+        if n < 0 || n as usize > self.data.len() {
+            return Err(format!(
+                "Cannot shift {} elements from deque of length {}.",
+                n,
+                self.data.len()
+            ));
+        }
+        for _ in 0..n {
+            self.move_(dir)?;
+        }
+        Ok(())
+    }
+
+    fn over(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, b);
+        self.push(dir, a);
+        self.push(dir, b);
+        Ok(())
+    }
+
+    /// Converts a position counted from the `dir` end (0 being the end
+    /// element itself) into an index into the deque.
+    fn index_from(&self, dir: &Direction, n: i64) -> Result<usize, String> {
+        let len = self.data.len();
+        if n < 0 || n as usize >= len {
+            return Err(format!(
+                "Index {} out of range for deque of length {}.",
+                n, len
+            ));
+        }
+        Ok(match dir {
+            Direction::Left => n as usize,
+            Direction::Right => len - 1 - n as usize,
+        })
+    }
+
+    /// Copies the n-th element from the `dir` end onto that end, so `0 pick`
+    /// behaves like `dup`.
+    fn pick(&mut self, dir: &Direction) -> Result<(), String> {
+        let n = self.pop(dir)?;
+        let index = self.index_from(dir, n)?;
+        self.push(dir, self.data[index]);
+        Ok(())
+    }
+
+    /// Moves the n-th element from the `dir` end onto that end, so `1 roll`
+    /// behaves like `swap`.
+    fn roll(&mut self, dir: &Direction) -> Result<(), String> {
+        let n = self.pop(dir)?;
+        let index = self.index_from(dir, n)?;
+        let a = self.data.remove(index).unwrap();
+        self.push(dir, a);
+        Ok(())
+    }
+
+    fn drop(&mut self, dir: &Direction) -> Result<(), String> {
+        self.pop(dir)?;
+        Ok(())
+    }
+
+    /// Arithmetic shift right: the sign bit is copied into the vacated bits,
+    /// so negative values stay negative.
+    fn shr(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        let shifted = u32::try_from(a)
+            .ok()
+            .and_then(|a| b.checked_shr(a))
+            .ok_or(format!("Cannot shift by {}.", a))?;
+        self.push(dir, shifted);
+        Ok(())
+    }
+
+    /// Logical shift right: the value is shifted as an unsigned bit pattern,
+    /// so the vacated bits are always zero.
+    fn shrl(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        let shifted = u32::try_from(a)
+            .ok()
+            .and_then(|a| (b as u64).checked_shr(a))
+            .ok_or(format!("Cannot shift by {}.", a))?;
+        self.push(dir, shifted as i64);
+        Ok(())
+    }
+
+    fn shl(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, b << a);
+        Ok(())
+    }
+
+    fn eq(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, (a == b) as i64);
+        Ok(())
+    }
+
+    fn or(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, a | b);
+        Ok(())
+    }
+
+    fn and(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, a & b);
+        Ok(())
+    }
+
+    fn xor(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, a ^ b);
+        Ok(())
+    }
+
+    fn neg(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let a = a.checked_neg().ok_or(format!("Cannot negate {}.", a))?;
+        self.push(dir, a);
+        Ok(())
+    }
+
+    fn not(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        self.push(dir, !a);
+        Ok(())
+    }
+
+    fn greater(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, (a > b) as i64);
+        Ok(())
+    }
+
+    fn less(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, (a < b) as i64);
+        Ok(())
+    }
+
+    fn greater_eq(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, (a >= b) as i64);
+        Ok(())
+    }
+
+    fn less_eq(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        let b = self.pop(dir)?;
+        self.push(dir, (a <= b) as i64);
+        Ok(())
+    }
+
+    fn dup(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        self.push(dir, a);
+        self.push(dir, a);
+        Ok(())
+    }
+
+    fn print(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        writeln!(self.output, "{}", a).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn printc(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = self.pop(dir)?;
+        writeln!(self.output, "{}", a as u8 as char).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Pops a level and a value and hands them to the registered logger, if
+    /// any.
+    fn log(&mut self, dir: &Direction) -> Result<(), String> {
+        let level = self.pop(dir)?;
+        let a = self.pop(dir)?;
+        if !(0..=2).contains(&level) {
+            return Err(format!("Invalid log level {}.", level));
+        }
+        if let Some(logger) = &mut self.logger {
+            logger(level as u8, a);
+        }
+        Ok(())
+    }
+
+    /// Pops a base and a value and prints the value in that base, without a
+    /// trailing newline.
+    fn printbase(&mut self, dir: &Direction) -> Result<(), String> {
+        let base = self.pop(dir)?;
+        let a = self.pop(dir)?;
+        if !(2..=36).contains(&base) {
+            return Err(format!("Invalid base {}.", base));
+        }
+        let mut n = a.unsigned_abs();
+        let mut digits = Vec::new();
+        loop {
+            digits.push(std::char::from_digit((n % base as u64) as u32, base as u32).unwrap());
+            n /= base as u64;
+            if n == 0 {
+                break;
+            }
+        }
+        if a < 0 {
+            digits.push('-');
+        }
+        let text = digits.iter().rev().collect::<String>();
+        write!(self.output, "{}", text).map_err(|e| e.to_string())
+    }
+
+    /// Reads another line of stdin into the input buffer, returning `false`
+    /// at end of input.
+    fn fill_input(&mut self) -> Result<bool, String> {
+        let mut line = String::new();
+        let read = self
+            .reader
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?;
+        self.input.extend(line.chars());
+        Ok(read > 0)
+    }
+
+    /// Takes the next whitespace-delimited token from the input buffer,
+    /// reading more lines as needed. Returns `None` at end of input.
+    fn next_token(&mut self) -> Result<Option<String>, String> {
+        loop {
+            while self.input.front().is_some_and(|c| c.is_whitespace()) {
+                self.input.pop_front();
+            }
+            if !self.input.is_empty() {
+                break;
+            }
+            if !self.fill_input()? {
+                return Ok(None);
+            }
+        }
+        let mut token = String::new();
+        while let Some(&c) = self.input.front() {
+            if c.is_whitespace() {
+                break;
+            }
+            token.push(c);
+            self.input.pop_front();
+        }
+        Ok(Some(token))
+    }
+
+    /// Pushes the next integer from the input, or -1 at end of input.
+    fn read(&mut self, dir: &Direction) -> Result<(), String> {
+        let a = match self.next_token()? {
+            Some(token) => token.parse::<i64>().map_err(|e| e.to_string())?,
+            None => -1,
+        };
+        self.push(dir, a);
+        Ok(())
+    }
+
+    /// Pops a base and pushes the next input token parsed in that base, or -1
+    /// at end of input.
+    fn readbase(&mut self, dir: &Direction) -> Result<(), String> {
+        let base = self.pop(dir)?;
+        if !(2..=36).contains(&base) {
+            return Err(format!("Invalid base {}.", base));
+        }
+        let a = match self.next_token()? {
+            Some(token) => i64::from_str_radix(&token, base as u32)
+                .map_err(|_| format!("Invalid base {} number {}.", base, token))?,
+            None => -1,
+        };
+        self.push(dir, a);
+        Ok(())
+    }
+
+    /// Pushes the next character from the input, or -1 at end of input.
+    fn readc(&mut self, dir: &Direction) -> Result<(), String> {
+        if self.input.is_empty() {
+            self.fill_input()?;
+        }
+        let a = self.input.pop_front().map_or(-1, |c| c as i64);
+        self.push(dir, a);
+        Ok(())
+    }
+
+    /// Advances the SplitMix64 generator.
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn rand(&mut self, dir: &Direction) -> Result<(), String> {
+        let n = self.pop(dir)?;
+        if n <= 0 {
+            return Err(format!("Random bound must be positive, got {}.", n));
+        }
+        let a = (self.next_random() % n as u64) as i64;
+        self.push(dir, a);
+        Ok(())
+    }
+
+    /// Pushes the milliseconds elapsed since the VM was created.
+    fn time(&mut self, dir: &Direction) {
+        let millis = self
+            .clock
+            .unwrap_or_else(|| self.start.elapsed().as_millis() as i64);
+        self.push(dir, millis);
+    }
+
+    fn memusage(&mut self, dir: &Direction) {
+        let bytes = self.data.len() * size_of::<i64>();
+        self.push(dir, bytes as i64);
+    }
+
+    fn dump(&mut self) -> Result<(), String> {
+        let values = self
+            .data
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if values.is_empty() {
+            writeln!(self.output, "L[ ]R")
+        } else {
+            writeln!(self.output, "L[ {} ]R", values)
+        }
+        .map_err(|e| e.to_string())
+    }
+
+    fn trace(&mut self) -> Result<(), String> {
+        let dots = self
+            .data
+            .iter()
+            .map(|x| if *x == 1 { '*' } else { ' ' })
+            .collect::<String>();
+        writeln!(self.output, "{}", dots).map_err(|e| e.to_string())
+    }
+
+    /// Runs the loaded program. A non-zero `exit` is reported as
+    /// `VmError::ExitCode`, separately from errors raised by the VM.
+    pub fn execute(&mut self) -> Result<(), VmError> {
+        let result = self.run();
+        self.output.flush().map_err(|e| e.to_string())?;
+        result.map_err(|e| match (e, self.program.get(self.ip as usize)) {
+            (VmError::Error(e), Some(instruction)) => {
+                VmError::Error(format!("{}: {}", instruction.location, e))
+            }
+            (e, _) => e,
+        })
+    }
+
+    fn run(&mut self) -> Result<(), VmError> {
+        let program_len = self.code.len() as i64;
+        while self.ip < program_len {
+            let (op, dir) = self.code[self.ip as usize];
+            if let Some(counts) = &mut self.counts {
+                counts[self.ip as usize] += 1;
+            }
+            if self.profiling {
+                *self.profile.entry(discriminant(&op)).or_default() += 1;
+            }
+            match op {
+                Op::Add => self.add(&dir)?,
+                Op::Sub => self.sub(&dir)?,
+                Op::Min => self.min(&dir)?,
+                Op::Max => self.max(&dir)?,
+                Op::DivMod => self.divmod(&dir)?,
+                Op::GcdAll => self.gcdall(&dir)?,
+                Op::Swap => self.swap(&dir)?,
+                Op::Move => self.move_(&dir)?,
+                Op::Shift => self.shift(&dir)?,
+                Op::Over => self.over(&dir)?,
+                Op::Drop => self.drop(&dir)?,
+                Op::Shr => self.shr(&dir)?,
+                Op::Shrl => self.shrl(&dir)?,
+                Op::Shl => self.shl(&dir)?,
+                Op::Eq => self.eq(&dir)?,
+                Op::Or => self.or(&dir)?,
+                Op::And => self.and(&dir)?,
+                Op::Xor => self.xor(&dir)?,
+                Op::Not => self.not(&dir)?,
+                Op::Neg => self.neg(&dir)?,
+                Op::Greater => self.greater(&dir)?,
+                Op::Less => self.less(&dir)?,
+                Op::GreaterEq => self.greater_eq(&dir)?,
+                Op::LessEq => self.less_eq(&dir)?,
+                Op::Dup => self.dup(&dir)?,
+                Op::Print => self.print(&dir)?,
+                Op::PrintC => self.printc(&dir)?,
+                Op::PrintBase => self.printbase(&dir)?,
+                Op::Log => self.log(&dir)?,
+                Op::Read => self.read(&dir)?,
+                Op::ReadC => self.readc(&dir)?,
+                Op::ReadBase => self.readbase(&dir)?,
+                Op::Ask => match &mut self.asker {
+                    Some(asker) => match asker() {
+                        Some(a) => self.push(&dir, a),
+                        // leave ip on this instruction so execute resumes here
+                        None => return Err(VmError::Yield),
+                    },
+                    None => self.read(&dir)?,
+                },
+                Op::Trace => self.trace()?,
+                Op::Dump => self.dump()?,
+                Op::Assert => self.assert(&dir)?,
+                Op::MemUsage => self.memusage(&dir),
+                Op::Compact => self.shrink_to_fit(),
+                Op::Rand => self.rand(&dir)?,
+                Op::Time => self.time(&dir),
+                Op::HereCount => {
+                    let count = self.counts.as_ref().map_or(0, |c| c[self.ip as usize]);
+                    self.push(&dir, count as i64);
+                }
+                Op::Pick => self.pick(&dir)?,
+                Op::Roll => self.roll(&dir)?,
+                Op::Jmp => {
+                    self.jmp(&dir)?;
+                    continue;
+                }
+                Op::JmpIf => {
+                    if self.jmpif(&dir)? {
+                        continue;
+                    }
+                }
+                Op::JmpIfZ => {
+                    if self.jmpifz(&dir)? {
+                        continue;
+                    }
+                }
+                Op::Restart => {
+                    self.restart(&dir)?;
+                    continue;
+                }
+                Op::Exit => {
+                    let code = self.pop(&dir)?;
+                    return exit(code);
+                }
+                Op::ExitIf => {
+                    let cond = self.pop(&dir)?;
+                    let code = self.pop(&dir)?;
+                    if cond != 0 {
+                        return exit(code);
+                    }
+                }
+                Op::Push(val) => self.push(&dir, val),
+            }
+            if DEBUG {
+                println!("data {:?}", self.data);
+            }
+            self.ip += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Why a program stopped before reaching its end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// The program ran `exit` with a non-zero code.
+    ExitCode(i64),
+    /// `ask` is waiting for the host. Calling `execute` again resumes at the
+    /// `ask`.
+    Yield,
+    /// The VM could not carry on, e.g. popping from an empty deque.
+    Error(String),
+}
+
+impl From<String> for VmError {
+    fn from(e: String) -> Self {
+        VmError::Error(e)
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::ExitCode(code) => write!(f, "Exit code {}", code),
+            VmError::Yield => write!(f, "Waiting for input from the host."),
+            VmError::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Builds the final program, giving each label the address of the
+/// instruction that follows it. Labels don't occupy an address of their own,
+/// so the addresses always match positions in the program.
+fn resolve_labels(items: Vec<Item>) -> (Vec<Instruction>, HashMap<String, i64>) {
+    let mut labels = HashMap::new();
+    let mut program = Vec::new();
+    for item in items {
+        match item {
+            Item::Label(label) => {
+                labels.insert(label, program.len() as i64);
+            }
+            Item::Instruction(instruction) => program.push(instruction),
+        }
+    }
+    (program, labels)
+}
+
+/// Lowers the parsed instructions into opcodes, pre-parsing literals and
+/// resolving label references to their addresses. An unknown instruction or
+/// a reference to a missing label fails here, before anything runs.
+fn compile(
+    program: &[Instruction],
+    labels: &HashMap<String, i64>,
+) -> Result<Vec<(Op, Direction)>, String> {
+    program
+        .iter()
+        .enumerate()
+        .map(|(ip, instruction)| {
+            let op = match OPCODES.iter().find(|(name, _)| *name == instruction.op) {
+                Some((_, op)) => *op,
+                None => {
+                    let val = instruction.op.as_str();
+                    if let Ok(val) = val.parse::<i64>() {
+                        // it's a value
+                        Op::Push(val)
+                    } else if let Some(addr) = labels.get(&val.to_ascii_lowercase()) {
+                        // it's a label reference, and labels are case-insensitive
+                        Op::Push(*addr)
+                    } else {
+                        return Err(format!(
+                            "{}: Unknown instruction {} (ip {}).",
+                            instruction.location, val, ip
+                        ));
+                    }
+                }
+            };
+            Ok((op, instruction.direction))
+        })
+        .collect()
+}
+
+/// Ends the program with `code`, treating zero as a clean exit.
+fn exit(code: i64) -> Result<(), VmError> {
+    if code != 0 {
+        return Err(VmError::ExitCode(code));
+    }
+    Ok(())
+}
+
+/// Runs `source` with `input` as its stdin and returns everything it printed.
+pub fn run(source: &str, input: &str) -> Result<String, VmError> {
+    let output = Capture::default();
+    let mut vm = VM::new();
+    vm.set_input(Cursor::new(input.to_owned()));
+    vm.set_output(output.clone());
+    vm.load_program(source.to_owned())?;
+    vm.execute()?;
+    let bytes = output.0.borrow();
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// An output sink whose contents can still be read after it is handed to a
+/// VM.
+#[derive(Default, Clone)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where a token came from: its file, if it was loaded from one, and its
+/// 1-based line.
+#[derive(Debug, Clone)]
+struct Location {
+    file: Option<Rc<Path>>,
+    line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}: line {}", file.display(), self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// A source token and where it came from.
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    location: Location,
+}
+
+/// Expands `def` and `include` lines into a flat list of tokens.
+///
+/// A definition's value is either a single literal, which later `NAME!` and
+/// `!NAME` tokens push in their direction, or a sequence of instructions that
+/// a bare `NAME` token is replaced with. Names used inside a value must
+/// already be defined. Definitions are shared with included files.
+#[derive(Default)]
+struct Preprocessor {
+    defs: HashMap<String, Vec<String>>,
+    included: HashSet<PathBuf>,
+    tokens: Vec<Token>,
+}
+
+impl Preprocessor {
+    /// Expands the file at `path`, resolving its includes relative to the
+    /// directory it lives in. Errors are prefixed with the file's path.
+    fn expand_file(&mut self, path: &Path) -> Result<(), String> {
+        let (path, program) = self.open(path)?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        self.expand(&program, base, Some(Rc::from(path.as_path())))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Expands an included file. Unlike `expand_file`, errors aren't prefixed
+    /// with its path, since the `include` line that names it already is.
+    fn include(&mut self, path: &Path) -> Result<(), String> {
+        let (path, program) = self.open(path)?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        self.expand(&program, base, Some(Rc::from(path.as_path())))
+    }
+
+    /// Reads the file at `path`, refusing to read the same file twice.
+    fn open(&mut self, path: &Path) -> Result<(PathBuf, String), String> {
+        let path = path
+            .canonicalize()
+            .map_err(|_| format!("Could not read {}.", path.display()))?;
+        if !self.included.insert(path.clone()) {
+            return Err(format!("{} is already included.", path.display()));
+        }
+        let program = std::fs::read_to_string(&path)
+            .map_err(|_| format!("Could not read {}.", path.display()))?;
+        Ok((path, program))
+    }
+
+    /// Expands `program`, resolving its includes relative to `base`. `file`
+    /// names the file it came from, if any.
+    fn expand(&mut self, program: &str, base: &Path, file: Option<Rc<Path>>) -> Result<(), String> {
+        for (line, text) in program.lines().enumerate() {
+            let line = line + 1;
+            let mut words = text.split_whitespace().peekable();
+            match words.peek() {
+                Some(&"def") => {
+                    words.next();
+                    self.define(words)
+                        .map_err(|e| format!("line {}: {}", line, e))?;
+                }
+                Some(&"include") => {
+                    words.next();
+                    let path = match (words.next(), words.next()) {
+                        (Some(path), None)
+                            if path.len() > 1 && path.starts_with('"') && path.ends_with('"') =>
+                        {
+                            &path[1..path.len() - 1]
+                        }
+                        _ => return Err(format!("line {}: include requires a quoted path.", line)),
+                    };
+                    self.include(&base.join(path))
+                        .map_err(|e| format!("line {}: include \"{}\": {}", line, path, e))?;
+                }
+                _ => {
+                    for word in words {
+                        let expansion = substitute(&self.defs, word)
+                            .map_err(|e| format!("line {}: {}", line, e))?
+                            .unwrap_or_else(|| vec![word.to_owned()]);
+                        self.tokens.extend(expansion.into_iter().map(|text| Token {
+                            text,
+                            location: Location {
+                                file: file.clone(),
+                                line,
+                            },
+                        }));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn define<'a>(&mut self, mut words: impl Iterator<Item = &'a str>) -> Result<(), String> {
+        let name = words.next();
+        let mut body = Vec::new();
+        for word in words {
+            match substitute(&self.defs, word)? {
+                Some(expansion) => body.extend(expansion),
+                None if is_bare(word) && word.parse::<i64>().is_err() => {
+                    return Err(format!("Undefined name {} in def.", word));
+                }
+                None => body.push(word.to_owned()),
+            }
+        }
+        match name {
+            Some(name) if name.parse::<i64>().is_ok() || name.contains(['!', ':']) => {
+                Err(format!("Invalid def name {}.", name))
+            }
+            Some(name) if !body.is_empty() => {
+                self.defs.insert(name.to_owned(), body);
+                Ok(())
+            }
+            _ => Err("def requires a name and a value.".to_owned()),
+        }
+    }
+}
+
+/// Returns the replacement for `word` if it names a definition.
+fn substitute(
+    defs: &HashMap<String, Vec<String>>,
+    word: &str,
+) -> Result<Option<Vec<String>>, String> {
+    if let Some(body) = defs.get(word) {
+        return Ok(Some(body.clone()));
+    }
+    let (name, left) = if let Some(name) = word.strip_prefix('!') {
+        (name, true)
+    } else if let Some(name) = word.strip_suffix('!') {
+        (name, false)
+    } else {
+        return Ok(None);
+    };
+    let Some(body) = defs.get(name) else {
+        return Ok(None);
+    };
+    match body.as_slice() {
+        [literal] if literal.parse::<i64>().is_ok() => Ok(Some(vec![if left {
+            format!("!{}", literal)
+        } else {
+            format!("{}!", literal)
+        }])),
+        _ => Err(format!("{} is not a literal and cannot be pushed.", name)),
+    }
+}
+
+fn is_bare(word: &str) -> bool {
+    !word.starts_with('!') && !word.ends_with('!') && !word.ends_with(':')
+}
+
+/// A parsed token: either a label definition or an instruction.
+#[derive(Debug, Clone)]
+enum Item {
+    Label(String),
+    Instruction(Instruction),
+}
+
+#[derive(Debug, Clone)]
+struct Instruction {
+    op: String,
+    direction: Direction,
+    location: Location,
+}
+
+/// Opcode names as written in source, e.g. `add!`.
+const OPCODES: &[(&str, Op)] = &[
+    ("add", Op::Add),
+    ("sub", Op::Sub),
+    ("min", Op::Min),
+    ("max", Op::Max),
+    ("divmod", Op::DivMod),
+    ("gcdall", Op::GcdAll),
+    ("swap", Op::Swap),
+    ("move", Op::Move),
+    ("shift", Op::Shift),
+    ("over", Op::Over),
+    ("drop", Op::Drop),
+    ("shr", Op::Shr),
+    ("shrl", Op::Shrl),
+    ("shl", Op::Shl),
+    ("eq", Op::Eq),
+    ("or", Op::Or),
+    ("and", Op::And),
+    ("xor", Op::Xor),
+    ("not", Op::Not),
+    ("neg", Op::Neg),
+    (">", Op::Greater),
+    ("<", Op::Less),
+    (">=", Op::GreaterEq),
+    ("<=", Op::LessEq),
+    ("dup", Op::Dup),
+    ("print", Op::Print),
+    ("printc", Op::PrintC),
+    ("printbase", Op::PrintBase),
+    ("log", Op::Log),
+    ("read", Op::Read),
+    ("readc", Op::ReadC),
+    ("readbase", Op::ReadBase),
+    ("ask", Op::Ask),
+    ("trace", Op::Trace),
+    ("dump", Op::Dump),
+    ("assert", Op::Assert),
+    ("memusage", Op::MemUsage),
+    ("compact", Op::Compact),
+    ("rand", Op::Rand),
+    ("time", Op::Time),
+    ("pick", Op::Pick),
+    ("roll", Op::Roll),
+    ("jmp", Op::Jmp),
+    ("jmpif", Op::JmpIf),
+    ("jmpifz", Op::JmpIfZ),
+    ("restart", Op::Restart),
+    ("here_count", Op::HereCount),
+    ("exit", Op::Exit),
+    ("exitif", Op::ExitIf),
+];
+
+/// Names an opcode for the profiler.
+fn op_name(op: Discriminant<Op>) -> &'static str {
+    OPCODES
+        .iter()
+        .find(|(_, o)| discriminant(o) == op)
+        .map_or("push", |(name, _)| name)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Add,
+    Sub,
+    Min,
+    Max,
+    DivMod,
+    GcdAll,
+    Swap,
+    Move,
+    Shift,
+    Over,
+    Drop,
+    Shr,
+    Shrl,
+    Shl,
+    Eq,
+    Or,
+    And,
+    Xor,
+    Not,
+    Neg,
+    Greater,
+    Less,
+    GreaterEq,
+    LessEq,
+    Dup,
+    Print,
+    PrintC,
+    PrintBase,
+    Log,
+    Read,
+    ReadC,
+    ReadBase,
+    Ask,
+    Trace,
+    Dump,
+    Assert,
+    MemUsage,
+    Compact,
+    Rand,
+    Time,
+    Pick,
+    Roll,
+    Jmp,
+    JmpIf,
+    JmpIfZ,
+    Restart,
+    HereCount,
+    Exit,
+    ExitIf,
+    Push(i64),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Left,
+    Right,
+}
+
+impl Direction {
+    fn invert(&self) -> Direction {
+        match self {
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+}
+
+const DEBUG: bool = false;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(source: &str) -> VM {
+        let mut vm = VM::new();
+        vm.load_program(source.to_owned()).unwrap();
+        vm
+    }
+
+    #[test]
+    fn memory_bytes_grows_with_deque() {
+        let mut vm = load("1! 2! 3! 4! 5! 6! 7! 8! 9! 10! 11! 12! 13! 14! 15! 16! 17!");
+        let before = vm.memory_bytes();
+        vm.execute().unwrap();
+        assert!(vm.memory_bytes() > before);
+    }
+
+    #[test]
+    fn compact_releases_capacity() {
+        let mut vm = load("compact!");
+        vm.data.extend(0..1000);
+        vm.data.clear();
+        let before = vm.data.capacity();
+        vm.execute().unwrap();
+        assert!(vm.data.capacity() < before);
+    }
+
+    #[test]
+    fn run_batch_resets_between_inputs() {
+        let mut vm = VM::new();
+        let results = vm.run_batch(
+            "read! read! add!",
+            vec!["1 2".to_owned(), "3\n4".to_owned(), "5".to_owned()],
+        );
+        assert_eq!(results[0], Ok(vec![3]));
+        assert_eq!(results[1], Ok(vec![7]));
+        assert_eq!(results[2], Ok(vec![4]));
+    }
+
+    #[test]
+    fn run_feeds_input_and_captures_output() {
+        assert_eq!(
+            run("read! read! add! print!", "20 22"),
+            Ok("42\n".to_owned())
+        );
+    }
+
+    #[test]
+    fn set_input_and_set_output_replace_stdio() {
+        let output = Capture::default();
+        let mut vm = load("readc! printc! read! print!");
+        vm.set_input(Cursor::new("x 7"));
+        vm.set_output(output.clone());
+        vm.execute().unwrap();
+        assert_eq!(*output.0.borrow(), b"x\n7\n");
+    }
+
+    #[test]
+    fn shr_and_shrl_diverge_on_negative_values() {
+        assert_eq!(run("-8! 1! shr! print!", ""), Ok("-4\n".to_owned()));
+        assert_eq!(
+            run("-8! 1! shrl! print!", ""),
+            Ok(format!("{}\n", (-8i64 as u64) >> 1))
+        );
+        assert_eq!(run("8! 1! shrl! print!", ""), Ok("4\n".to_owned()));
+    }
+
+    #[test]
+    fn shrl_rejects_out_of_range_shifts() {
+        assert!(run("1! 64! shrl!", "").is_err());
+        assert!(run("1! -1! shrl!", "").is_err());
+        assert!(run("1! 64! shr!", "").is_err());
+        assert!(run("1! -1! shr!", "").is_err());
+    }
+
+    #[test]
+    fn profile_groups_pushes_under_one_name() {
+        let mut vm = load("top: 1! 2! add! 3! add! drop! 0! top! jmpif!");
+        vm.set_profiling(true);
+        vm.execute().unwrap();
+        assert_eq!(
+            vm.profile(),
+            vec![("push", 5), ("add", 2), ("drop", 1), ("jmpif", 1)]
+        );
+    }
+
+    #[test]
+    fn def_expands_literals_and_sequences() {
+        let source = "def W 40\ndef INC 1! add!\nW! INC !W dump!";
+        assert_eq!(run(source, ""), Ok("L[ 40, 41 ]R\n".to_owned()));
+    }
+
+    #[test]
+    fn def_rejects_invalid_names() {
+        for source in ["def 5 6", "def a! 6", "def !a 6", "def a: 6", "def X Y"] {
+            assert!(
+                VM::new().load_program(source.to_owned()).is_err(),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn errors_in_included_files_name_the_file() {
+        let dir = std::env::temp_dir().join(format!("deque-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("main.deque"),
+            "include \"lib.deque\"\n\n1! print!\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("lib.deque"), "\ndrop!\n").unwrap();
+        let mut vm = VM::new();
+        vm.set_output(Capture::default());
+        vm.load_file(&dir.join("main.deque")).unwrap();
+        let lib = dir.join("lib.deque").canonicalize().unwrap();
+        assert_eq!(
+            vm.execute(),
+            Err(VmError::Error(format!(
+                "{}: line 2: Could not pop from back of deque.",
+                lib.display()
+            )))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restart_reruns_program_until_counter_limit() {
+        let output = Capture::default();
+        let mut vm = load("1! add! dup! print! dup! 3! swap! >=! done! jmpif! 0! restart! done:");
+        vm.set_output(output.clone());
+        vm.data.push_back(0);
+        vm.execute().unwrap();
+        assert_eq!(*output.0.borrow(), b"1\n2\n3\n");
+        assert_eq!(vm.data, [3]);
+    }
+
+    #[test]
+    fn restart_can_clear_the_deque() {
+        let source = "read! dup! -1! eq! end! jmpif! print! 1! restart! end: dump!";
+        assert_eq!(run(source, "7 8"), Ok("7\n8\nL[ -1 ]R\n".to_owned()));
+    }
+
+    #[test]
+    fn memory_bytes_counts_execution_counts() {
+        let plain = load("1! drop!").memory_bytes();
+        let counted = load("here_count! drop!").memory_bytes();
+        assert!(counted > plain);
+    }
+
+    #[test]
+    fn here_count_increases_each_iteration() {
+        let source = "loop: here_count! dup! print! 3! >! loop! jmpif!";
+        assert_eq!(run(source, ""), Ok("1\n2\n3\n".to_owned()));
+    }
+
+    #[test]
+    fn divmod_rounds_toward_zero() {
+        assert_eq!(
+            run("17! 5! divmod! dump!", ""),
+            Ok("L[ 3, 2 ]R\n".to_owned())
+        );
+        assert_eq!(
+            run("-7! 2! divmod! dump!", ""),
+            Ok("L[ -3, -1 ]R\n".to_owned())
+        );
+        assert_eq!(
+            run("7! -2! divmod! dump!", ""),
+            Ok("L[ -3, 1 ]R\n".to_owned())
+        );
+        assert_eq!(
+            run("-7! -2! divmod! dump!", ""),
+            Ok("L[ 3, -1 ]R\n".to_owned())
+        );
+    }
+
+    #[test]
+    fn divmod_rejects_zero_divisor() {
+        assert!(run("1! 0! divmod!", "").is_err());
+    }
+
+    #[test]
+    fn labels_address_the_following_instruction() {
+        let source = "def TEN 10\nstart: a: 1!\ndef SKIP over! jmp!\nb: TEN! c:\n2!\nend:";
+        let vm = load(source);
+        assert_eq!(vm.labels["start"], 0);
+        assert_eq!(vm.labels["a"], 0);
+        assert_eq!(vm.labels["b"], 1);
+        assert_eq!(vm.labels["c"], 2);
+        assert_eq!(vm.labels["end"], 3);
+        assert_eq!(vm.program.len(), 3);
+    }
+
+    #[test]
+    fn jumps_land_on_labels_after_def_lines() {
+        let source = "def SKIP skip! jmp!\n1!\nSKIP\n99! print!\ndef X 5\nskip: print!";
+        assert_eq!(run(source, ""), Ok("1\n".to_owned()));
+    }
+
+    #[test]
+    fn printbase_writes_digits_without_newline() {
+        assert_eq!(
+            run("255! 16! printbase! 10! 2! printbase!", ""),
+            Ok("ff1010".to_owned())
+        );
+        assert_eq!(run("-255! 16! printbase!", ""), Ok("-ff".to_owned()));
+        assert!(run("1! 37! printbase!", "").is_err());
+    }
+
+    #[test]
+    fn exit_is_distinct_from_errors() {
+        assert_eq!(run("3! exit!", ""), Err(VmError::ExitCode(3)));
+        assert!(matches!(run("drop!", ""), Err(VmError::Error(_))));
+        assert_eq!(run("0! exit! 1! print!", ""), Ok(String::new()));
+    }
+
+    #[test]
+    fn time_reads_the_injected_clock() {
+        let mut vm = load("time! print!");
+        let output = Capture::default();
+        vm.set_output(output.clone());
+        vm.set_clock(Some(42));
+        vm.execute().unwrap();
+        assert_eq!(*output.0.borrow(), b"42\n");
+    }
+
+    #[test]
+    fn readbase_parses_in_the_given_base() {
+        assert_eq!(
+            run("16! readbase! print! 2! readbase! print!", "ff 1010\n"),
+            Ok("255\n10\n".to_owned())
+        );
+        assert_eq!(run("16! readbase! print!", ""), Ok("-1\n".to_owned()));
+        assert!(run("2! readbase!", "12\n").is_err());
+    }
+
+    #[test]
+    fn exitif_exits_only_when_truthy() {
+        assert_eq!(
+            run("7! 1! exitif! 2! print!", ""),
+            Err(VmError::ExitCode(7))
+        );
+        assert_eq!(run("7! 0! exitif! 2! print!", ""), Ok("2\n".to_owned()));
+    }
+
+    #[test]
+    fn dirpush_left_applies_to_unmarked_tokens() {
+        let mut vm = load("9! dirpush left 1 2 add 3 dirpop 4!");
+        vm.execute().unwrap();
+        assert_eq!(vm.data, [3, 3, 9, 4]);
+    }
+
+    #[test]
+    fn failed_reload_keeps_the_previous_program() {
+        let mut vm = load("1! 2! add! print!");
+        let output = Capture::default();
+        vm.set_output(output.clone());
+        vm.set_profiling(true);
+        assert!(vm.load_program("1! 2! 3! 4! nope!".to_owned()).is_err());
+        vm.execute().unwrap();
+        assert_eq!(*output.0.borrow(), b"3\n");
+    }
+
+    #[test]
+    fn unknown_instructions_are_not_reported_as_labels() {
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.load_program("1! prnt!".to_owned()),
+            Err("line 1: Unknown instruction prnt (ip 1).".to_owned())
+        );
+    }
+
+    #[test]
+    fn label_references_ignore_case() {
+        let source = "3! Loop: 1! sub! dup! print! dup! LOOP! jmpif! drop!";
+        assert_eq!(run(source, ""), Ok("2\n1\n0\n".to_owned()));
+        assert_eq!(
+            run("Loop: 1! print! 0! Loop! jmpif!", ""),
+            Ok("1\n".to_owned())
+        );
+    }
+
+    #[test]
+    fn logger_receives_level_and_value() {
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = load("5! 0! log! 7! 2! log!");
+        let sink = records.clone();
+        vm.set_logger(move |level, value| sink.borrow_mut().push((level, value)));
+        vm.execute().unwrap();
+        assert_eq!(*records.borrow(), [(0, 5), (2, 7)]);
+    }
+
+    #[test]
+    fn ask_pushes_the_callback_value() {
+        let mut vm = load("ask! 1! add! print!");
+        let output = Capture::default();
+        vm.set_output(output.clone());
+        vm.set_ask_callback(|| Some(41));
+        vm.execute().unwrap();
+        assert_eq!(*output.0.borrow(), b"42\n");
+    }
+
+    #[test]
+    fn ask_yields_and_resumes_when_the_callback_has_no_value() {
+        let mut vm = load("1! print! ask! print!");
+        let output = Capture::default();
+        vm.set_output(output.clone());
+        let mut answers = vec![Some(9), None];
+        vm.set_ask_callback(move || answers.pop().flatten());
+        assert_eq!(vm.execute(), Err(VmError::Yield));
+        assert_eq!(*output.0.borrow(), b"1\n");
+        vm.execute().unwrap();
+        assert_eq!(*output.0.borrow(), b"1\n9\n");
+    }
+
+    #[test]
+    fn gcdall_replaces_the_deque_with_its_gcd() {
+        let mut vm = load("12! -18! 30! gcdall!");
+        vm.execute().unwrap();
+        assert_eq!(vm.data, [6]);
+        let mut vm = load("7! 9! gcdall!");
+        vm.execute().unwrap();
+        assert_eq!(vm.data, [1]);
+    }
+
+    #[test]
+    fn gcdall_overflow_leaves_the_deque_intact() {
+        let mut vm = load("-9223372036854775808! 0! gcdall!");
+        assert!(vm.execute().is_err());
+        assert_eq!(vm.data, [i64::MIN, 0]);
+    }
+
+    #[test]
+    fn jmpif_takes_any_nonzero_condition() {
+        let program =
+            |cond: i64, op: &str| format!("{}! skip! {}! 0! print! skip: 1! print!", cond, op);
+        for cond in [-1, 1, 2] {
+            assert_eq!(run(&program(cond, "jmpif"), ""), Ok("1\n".to_owned()));
+            assert_eq!(run(&program(cond, "jmpifz"), ""), Ok("0\n1\n".to_owned()));
+        }
+        assert_eq!(run(&program(0, "jmpif"), ""), Ok("0\n1\n".to_owned()));
+        assert_eq!(run(&program(0, "jmpifz"), ""), Ok("1\n".to_owned()));
+        assert_eq!(
+            run("0! not! skip! jmpif! 0! print! skip: 1! print!", ""),
+            Ok("1\n".to_owned())
+        );
+    }
+
+    #[test]
+    fn assert_fails_only_on_zero() {
+        assert_eq!(run("1! assert! 5! print!", ""), Ok("5\n".to_owned()));
+        assert_eq!(
+            run("1! assert! 0! assert!", ""),
+            Err(VmError::Error(
+                "line 1: Assertion failed at ip 3.".to_owned()
+            ))
+        );
+    }
+
+    /// Writes `files` into a fresh directory under the system temp dir.
+    fn scratch(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("deque-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, text) in files {
+            std::fs::write(dir.join(file), text).unwrap();
+        }
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn include_errors_name_the_include_line_once() {
+        let dir = scratch(
+            "include-errors",
+            &[
+                ("main.deque", "1! print!\ninclude \"lib.deque\"\n"),
+                ("lib.deque", "\ndef 1 2\n"),
+                ("missing.deque", "include \"nope.deque\"\n"),
+            ],
+        );
+        let main = dir.join("main.deque");
+        assert_eq!(
+            VM::new().load_file(&main),
+            Err(format!(
+                "{}: line 2: include \"lib.deque\": line 2: Invalid def name 1.",
+                main.display()
+            ))
+        );
+        let missing = dir.join("missing.deque");
+        assert_eq!(
+            VM::new().load_file(&missing),
+            Err(format!(
+                "{}: line 1: include \"nope.deque\": Could not read {}.",
+                missing.display(),
+                dir.join("nope.deque").display()
+            ))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn labels_in_included_files_can_be_jumped_to() {
+        let dir = scratch(
+            "include-labels",
+            &[
+                (
+                    "main.deque",
+                    "1! print!\ninclude \"lib.deque\"\nshow! jmp!\n2! print!\nend:\n",
+                ),
+                (
+                    "lib.deque",
+                    "skip! jmp!\nshow: 7! print! end! jmp!\nskip:\n",
+                ),
+            ],
+        );
+        let output = Capture::default();
+        let mut vm = VM::new();
+        vm.set_output(output.clone());
+        vm.load_file(&dir.join("main.deque")).unwrap();
+        vm.execute().unwrap();
+        assert_eq!(*output.0.borrow(), b"1\n7\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repeated_includes_are_rejected() {
+        let dir = scratch(
+            "include-cycles",
+            &[
+                ("self.deque", "include \"self.deque\"\n"),
+                ("a.deque", "include \"b.deque\"\n"),
+                ("b.deque", "include \"a.deque\"\n"),
+            ],
+        );
+        let this = dir.join("self.deque");
+        assert_eq!(
+            VM::new().load_file(&this),
+            Err(format!(
+                "{}: line 1: include \"self.deque\": {} is already included.",
+                this.display(),
+                this.display()
+            ))
+        );
+        let a = dir.join("a.deque");
+        assert_eq!(
+            VM::new().load_file(&a),
+            Err(format!(
+                "{}: line 1: include \"b.deque\": line 1: include \"a.deque\": {} is already included.",
+                a.display(),
+                a.display()
+            ))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn seeded_rand_is_reproducible_and_in_range() {
+        let source = "20! top: 10! rand! print! 1! sub! dup! top! jmpif!";
+        let sequence = || {
+            let output = Capture::default();
+            let mut vm = VM::with_seed(42);
+            vm.set_output(output.clone());
+            vm.load_program(source.to_owned()).unwrap();
+            vm.execute().unwrap();
+            let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+            text.lines()
+                .map(|line| line.parse::<i64>().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let values = sequence();
+        assert_eq!(values.len(), 20);
+        assert!(values.iter().all(|value| (0..10).contains(value)));
+        assert_eq!(values, sequence());
+    }
+
+    #[test]
+    fn rand_rejects_non_positive_bounds() {
+        assert!(run("0! rand!", "").is_err());
+        assert!(run("-1! rand!", "").is_err());
+    }
+
+    /// Runs `source` and returns the deque it leaves behind.
+    fn deque_after(source: &str) -> VecDeque<i64> {
+        let mut vm = load(source);
+        vm.execute().unwrap();
+        vm.data
+    }
+
+    #[test]
+    fn pick_and_roll_count_from_their_own_end() {
+        assert_eq!(deque_after("1! 2! 3! 4! 1! pick!"), [1, 2, 3, 4, 3]);
+        assert_eq!(deque_after("1! 2! 3! 4! !1 !pick"), [2, 1, 2, 3, 4]);
+        assert_eq!(deque_after("1! 2! 3! 4! 2! roll!"), [1, 3, 4, 2]);
+        assert_eq!(deque_after("1! 2! 3! 4! !2 !roll"), [3, 1, 2, 4]);
+    }
+
+    #[test]
+    fn pick_and_roll_generalize_dup_and_swap() {
+        assert_eq!(
+            deque_after("1! 2! 3! 0! pick!"),
+            deque_after("1! 2! 3! dup!")
+        );
+        assert_eq!(
+            deque_after("1! 2! 3! !0 !pick"),
+            deque_after("1! 2! 3! !dup")
+        );
+        assert_eq!(
+            deque_after("1! 2! 3! 1! roll!"),
+            deque_after("1! 2! 3! swap!")
+        );
+        assert_eq!(
+            deque_after("1! 2! 3! !1 !roll"),
+            deque_after("1! 2! 3! !swap")
+        );
+    }
+
+    #[test]
+    fn pick_and_roll_reject_out_of_range_indices() {
+        for op in ["pick", "roll"] {
+            assert!(run(&format!("1! 2! 2! {}!", op), "").is_err());
+            assert!(run(&format!("1! 2! -1! {}!", op), "").is_err());
+        }
+    }
+
+    #[test]
+    fn while_loops_count_down() {
+        let source = "3! dup! while! dup! print! 1! sub! dup! endwhile! drop!";
+        assert_eq!(run(source, ""), Ok("3\n2\n1\n".to_owned()));
+    }
+
+    #[test]
+    fn nested_while_loops_match_innermost_first() {
+        let inner = "3! dup! while! !1 !add 1! sub! dup! endwhile! drop!";
+        let source = format!(
+            "!0 2! dup! while! {} 1! sub! dup! endwhile! drop! !print",
+            inner
+        );
+        assert_eq!(run(&source, ""), Ok("6\n".to_owned()));
+    }
+
+    #[test]
+    fn while_skips_the_body_when_the_condition_starts_false() {
+        let source = "0! while! 1! print! 0! endwhile! 2! print!";
+        assert_eq!(run(source, ""), Ok("2\n".to_owned()));
+    }
+
+    #[test]
+    fn unmatched_while_and_endwhile_fail_to_load() {
+        assert!(VM::new().load_program("while!".to_owned()).is_err());
+        assert!(VM::new().load_program("endwhile!".to_owned()).is_err());
+    }
+
+    #[test]
+    fn shift_reverses_the_moved_elements() {
+        assert_eq!(
+            run("1! 2! 3! 4! 5! 3! shift! dump!", ""),
+            Ok("L[ 3, 4, 5, 1, 2 ]R\n".to_owned())
+        );
+    }
+
+    #[test]
+    fn shift_rejects_bad_counts() {
+        assert!(run("9! shift!", "").is_err());
+        assert!(run("1! 2! -1! shift!", "").is_err());
+    }
+}